@@ -1,24 +1,42 @@
 //! A set of traits that can be used to describe a generic blockchain.
 
-// TODO: fallible decoding
-trait ByteEncodable: Into<Vec<u8>> + for<'a> From<&'a [u8]> {}
+/// A type which can be encoded to and decoded from bytes. Decoding is
+/// fallible, since the bytes may not describe a valid value.
+trait ByteEncodable: Into<Vec<u8>> + Sized {
+    /// The kind of error produced by a failed decode.
+    type DecodeError;
 
-/// A generic block. These contain transactions, a number, and a unique identifier.
-trait Block: Sized + ByteEncodable {
-    /// The type of transaction this kind of block stores.
-    type Transaction: Clone;
+    /// Attempt to decode a value from its byte representation.
+    fn decode(bytes: &[u8]) -> Result<Self, Self::DecodeError>;
+}
 
-    /// The type of unique identifier for this block, usually a hash.
+/// A block header: the parent, number, and identifier of a block, without its
+/// transaction body. Light clients and header-first sync can validate a chain
+/// of these alone, downloading full bodies lazily (or not at all).
+trait Header: Sized + ByteEncodable {
+    /// The type of unique identifier for this header, usually a hash.
     type Id: Eq;
 
-    /// This block's parent, referred to by Id.
+    /// This header's parent, referred to by Id.
     fn parent(&self) -> Self::Id;
 
-    /// This block's number. Assumed to start at 0, a genesis, and proceed incrementally from there.
+    /// This header's number. Assumed to start at 0, a genesis, and proceed incrementally from there.
     fn number(&self) -> u64;
 
-    /// Get the identifier for this block.
+    /// Get the identifier for this header.
     fn id(&self) -> Self::Id;
+}
+
+/// A generic block. These contain transactions and a header.
+trait Block: Sized + ByteEncodable {
+    /// The type of transaction this kind of block stores.
+    type Transaction: Clone;
+
+    /// The header type for this kind of block.
+    type Header: Header;
+
+    /// This block's header.
+    fn header(&self) -> &Self::Header;
 
     /// The transactions contained in this block.
     fn transactions(&self) -> &[Self::Transaction];
@@ -26,11 +44,22 @@ trait Block: Sized + ByteEncodable {
 
 /// A block which has uncles.
 trait HasUncles: Block {
-    /// The type of uncle this has.
-    type Uncle;
+    /// Get the headers of this block's uncles, for validation against the
+    /// uncle-ancestry rules in `Verifier::verify_family`.
+    fn uncle_headers(&self) -> Vec<Self::Header>;
+}
 
-    /// Get a list of uncle IDs.
-    fn uncles(&self) -> Vec<Self::Uncle>;
+/// An ordered route to move the canonical chain head from one block to
+/// another, as computed by `BlockProvider::tree_route`.
+struct TreeRoute<Id> {
+    /// Blocks to retract, ordered from the original head down to (but not
+    /// including) the ancestor.
+    pub retracted: Vec<Id>,
+    /// The common ancestor of both blocks.
+    pub ancestor: Id,
+    /// Blocks to enact, ordered from (but not including) the ancestor up to the
+    /// new head.
+    pub enacted: Vec<Id>,
 }
 
 /// A provider for block data.
@@ -42,21 +71,102 @@ trait BlockProvider {
 
     /// Try to fetch raw block data by id.
     /// Returns `None` if it doesn't exist.
-    fn block(&self, id: &<Self::Block as Block>::Id) -> Option<Self::Block>;
+    fn block(&self, id: &<<Self::Block as Block>::Header as Header>::Id) -> Option<Self::Block>;
 
     /// Get the id for a given block number.
     /// Return `None` if it doesn't exist.
-    fn block_id(&self, num: u64) -> Option<<Self::Block as Block>::Id>;
+    fn block_id(&self, num: u64) -> Option<<<Self::Block as Block>::Header as Header>::Id>;
 
-    /// Get the uncles for a given block.
-    fn uncles(&self, id: &<Self::Block as Block>::Id) -> Option<Vec<<Self::Block as HasUncles>::Uncle>> where Self::Block: HasUncles {
-        self.block(id).map(|b| b.uncles())
+    /// Get the uncle headers for a given block.
+    fn uncles(&self, id: &<<Self::Block as Block>::Header as Header>::Id) -> Option<Vec<<Self::Block as Block>::Header>> where Self::Block: HasUncles {
+        self.block(id).map(|b| b.uncle_headers())
     }
 
     /// Get the transactions for a given block.
-    fn transactions(&self, id: &<Self::Block as Block>::Id) -> Option<Vec<<Self::Block as Block>::Transaction>> {
+    fn transactions(&self, id: &<<Self::Block as Block>::Header as Header>::Id) -> Option<Vec<<Self::Block as Block>::Transaction>> {
         self.block(id).map(|b| b.transactions().to_vec())
     }
+
+    /// Compute the route to move the canonical head from `from` to `to`, in
+    /// terms of blocks to retract and blocks to enact.
+    ///
+    /// Returns `None` if `from`, `to`, or any ancestor walked while searching
+    /// for their common ancestor is missing from this provider.
+    fn tree_route(
+        &self,
+        from: &<<Self::Block as Block>::Header as Header>::Id,
+        to: &<<Self::Block as Block>::Header as Header>::Id,
+    ) -> Option<TreeRoute<<<Self::Block as Block>::Header as Header>::Id>> {
+        let mut from_block = self.block(from)?;
+        let mut to_block = self.block(to)?;
+
+        let mut retracted = Vec::new();
+        let mut enacted = Vec::new();
+
+        while from_block.header().number() > to_block.header().number() {
+            retracted.push(from_block.header().id());
+            from_block = self.block(&from_block.header().parent())?;
+        }
+
+        while to_block.header().number() > from_block.header().number() {
+            enacted.push(to_block.header().id());
+            to_block = self.block(&to_block.header().parent())?;
+        }
+
+        while from_block.header().id() != to_block.header().id() {
+            retracted.push(from_block.header().id());
+            enacted.push(to_block.header().id());
+            from_block = self.block(&from_block.header().parent())?;
+            to_block = self.block(&to_block.header().parent())?;
+        }
+
+        enacted.reverse();
+
+        Some(TreeRoute {
+            retracted: retracted,
+            ancestor: from_block.header().id(),
+            enacted: enacted,
+        })
+    }
+
+    /// The ids of up to `count` ancestors of `parent`, most recent first.
+    /// Stops early if an ancestor is missing from this provider.
+    fn last_hashes(
+        &self,
+        parent: &<<Self::Block as Block>::Header as Header>::Id,
+        count: usize,
+    ) -> Vec<<<Self::Block as Block>::Header as Header>::Id> {
+        let mut hashes = Vec::with_capacity(count);
+        let mut current = self.block(parent).map(|b| b.header().parent());
+
+        while hashes.len() < count {
+            let id = match current {
+                Some(id) => id,
+                None => break,
+            };
+
+            let block = match self.block(&id) {
+                Some(block) => block,
+                None => break,
+            };
+
+            hashes.push(id);
+            current = Some(block.header().parent());
+        }
+
+        hashes
+    }
+}
+
+/// The context a `Verifier` needs for phase 3 family verification.
+struct FamilyParams<'a, B: Block + 'a> {
+    /// The block being verified.
+    pub block: &'a B,
+    /// A provider for fetching the block's parent and uncles.
+    pub block_provider: &'a BlockProvider<Block = B>,
+    /// The ids of the most recent ancestors of the block's parent, most recent
+    /// first. See `BlockProvider::last_hashes`.
+    pub last_hashes: &'a [<B::Header as Header>::Id],
 }
 
 /// Verifier for a given type of block.
@@ -74,7 +184,13 @@ trait Verifier<B: Block> {
     /// Phase 3 verification: perform checks based on this block as well as its "family".
     /// Different chains have different notions of block family, so this may include uncles,
     /// the parent block, or other ancestors.
-    fn verify_family(&self, block: &B, provider: &BlockProvider<Block=B>) -> Result<(), Self::Error>;
+    fn verify_family(&self, params: &FamilyParams<B>) -> Result<(), Self::Error>;
+
+    /// Verify a header on its own, without the rest of the block body. This
+    /// lets light clients and header-first sync validate a chain of headers
+    /// (number bounds, gas limits, uncle headers, and the like) before, or
+    /// without ever, downloading the full block.
+    fn verify_header(&self, header: &B::Header) -> Result<(), Self::Error>;
 }
 
 /// The global state manipulated by blocks.
@@ -82,11 +198,439 @@ trait State {
     type Block: Block;
     type Error;
 
-    /// enact a pre-verified block. In case of failure, changes must not be applied.
-    fn enact(&mut self, block: Self::Block) -> Result<(), Self::Error>;
+    /// The receipt produced by executing a single transaction.
+    type Receipt;
+
+    /// Open a checkpoint, recording the current state so it can later be
+    /// discarded or rolled back to. Checkpoints may be nested.
+    fn checkpoint(&mut self);
+
+    /// Discard the most recently opened checkpoint, keeping all changes made
+    /// since it was opened.
+    fn discard_checkpoint(&mut self);
+
+    /// Roll back to the most recently opened checkpoint, undoing all changes
+    /// made since it was opened.
+    fn revert_to_checkpoint(&mut self);
+
+    /// Enact a pre-verified block, returning the receipt produced by each of
+    /// its transactions in order. In case of failure, changes must not be
+    /// applied: implementations should checkpoint before attempting the block
+    /// and revert on error.
+    fn enact(&mut self, block: Self::Block) -> Result<Vec<Self::Receipt>, Self::Error>;
 }
 
 trait Chain {
     type Block: Block;
     type Verifier: Verifier<Self::Block>;
+}
+
+/// The status of a block known to a `VerificationQueue`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    /// Queued for, or undergoing, phase 2 verification.
+    Unverified,
+    /// Passed phase 2 verification, awaiting phase 3 and enactment.
+    Verified,
+    /// Rejected by some phase of verification.
+    Bad,
+    /// Nothing is known about this id.
+    Unknown,
+}
+
+/// Counts of blocks at each stage of a `VerificationQueue`.
+#[derive(Debug, Clone, Copy, Default)]
+struct QueueInfo {
+    /// Blocks which have passed phase 1 but not yet phase 2 verification.
+    pub unverified: usize,
+    /// Blocks currently undergoing phase 2 verification on worker threads.
+    pub verifying: usize,
+    /// Blocks which have passed phase 2 verification and await phase 3 and enactment.
+    pub verified: usize,
+}
+
+/// A block that has passed `Verifier::verify_unordered` and is ready for phase 3
+/// verification immediately before enactment.
+struct Preverified<B> {
+    block: B,
+}
+
+impl<B> Preverified<B> {
+    /// The preverified block.
+    fn block(&self) -> &B {
+        &self.block
+    }
+}
+
+/// The error type produced by a `Chain`'s `Verifier`.
+type VerifyError<C> = <<C as Chain>::Verifier as Verifier<<C as Chain>::Block>>::Error;
+
+/// The outcome of phase 3 verification: the block, ready for `State::enact`.
+type VerifyResult<C> = Result<<C as Chain>::Block, VerifyError<C>>;
+
+/// Drives a `Chain`'s `Verifier` through its three verification phases as a
+/// staged pipeline: `import` runs phase 1 synchronously, `next_unverified`/
+/// `complete_verification` drive phase 2 (meant to run on worker threads), and
+/// `verify_and_pop` runs phase 3 immediately before handing a block to
+/// `State::enact`.
+struct VerificationQueue<C: Chain> {
+    verifier: C::Verifier,
+    unverified: Vec<C::Block>,
+    verifying: Vec<<<C::Block as Block>::Header as Header>::Id>,
+    verified: Vec<Preverified<C::Block>>,
+    bad: Vec<<<C::Block as Block>::Header as Header>::Id>,
+}
+
+impl<C: Chain> VerificationQueue<C> {
+    /// Create a new, empty queue driven by the given verifier.
+    fn new(verifier: C::Verifier) -> Self {
+        VerificationQueue {
+            verifier: verifier,
+            unverified: Vec::new(),
+            verifying: Vec::new(),
+            verified: Vec::new(),
+            bad: Vec::new(),
+        }
+    }
+
+    /// Phase 1: run cheap checks on `block` and, on success, queue it for phase 2
+    /// verification. Rejects immediately on failure.
+    fn import(&mut self, block: C::Block) -> Result<(), VerifyError<C>> {
+        self.verifier.verify_basic(&block)?;
+        self.unverified.push(block);
+        Ok(())
+    }
+
+    /// Take the next block off the unverified queue for phase 2 verification,
+    /// marking it as verifying. Intended to be called by a worker thread.
+    fn next_unverified(&mut self) -> Option<C::Block> {
+        if self.unverified.is_empty() {
+            return None;
+        }
+        let block = self.unverified.remove(0);
+        self.verifying.push(block.header().id());
+        Some(block)
+    }
+
+    /// Run phase 2 verification on `block`. Callers holding a block from
+    /// `next_unverified` should call this and then report the result back
+    /// through `complete_verification`.
+    fn verify_unordered(&self, block: &C::Block) -> Result<(), VerifyError<C>> {
+        self.verifier.verify_unordered(block)
+    }
+
+    /// Report the result of phase 2 verification for a block previously taken
+    /// from `next_unverified`. On success the block becomes available for phase
+    /// 3 verification; on failure its id is recorded as bad.
+    fn complete_verification(
+        &mut self,
+        block: C::Block,
+        result: Result<(), VerifyError<C>>,
+    ) {
+        let id = block.header().id();
+        if let Some(pos) = self.verifying.iter().position(|i| i == &id) {
+            self.verifying.remove(pos);
+        }
+        match result {
+            Ok(()) => self.verified.push(Preverified { block: block }),
+            Err(_) => self.bad.push(block.header().id()),
+        }
+    }
+
+    /// Phase 3: pop the next preverified block and run `verify_family` against
+    /// `provider`. On success the block is ready for `State::enact`.
+    fn verify_and_pop(
+        &mut self,
+        provider: &BlockProvider<Block = C::Block>,
+        last_hashes: &[<<C::Block as Block>::Header as Header>::Id],
+    ) -> Option<VerifyResult<C>> {
+        if self.verified.is_empty() {
+            return None;
+        }
+        let block = self.verified.remove(0).block;
+        let params = FamilyParams {
+            block: &block,
+            block_provider: provider,
+            last_hashes: last_hashes,
+        };
+
+        match self.verifier.verify_family(&params) {
+            Ok(()) => Some(Ok(block)),
+            Err(e) => {
+                self.bad.push(block.header().id());
+                Some(Err(e))
+            }
+        }
+    }
+
+    /// Current counts of blocks at each stage of the pipeline.
+    fn queue_info(&self) -> QueueInfo {
+        QueueInfo {
+            unverified: self.unverified.len(),
+            verifying: self.verifying.len(),
+            verified: self.verified.len(),
+        }
+    }
+
+    /// The verification status of a given block id, if anything is known about it.
+    fn status(&self, id: &<<C::Block as Block>::Header as Header>::Id) -> Status {
+        if self.unverified.iter().any(|b| &b.header().id() == id)
+            || self.verifying.iter().any(|i| i == id)
+        {
+            Status::Unverified
+        } else if self.verified.iter().any(|p| &p.block.header().id() == id) {
+            Status::Verified
+        } else if self.bad.iter().any(|b| b == id) {
+            Status::Bad
+        } else {
+            Status::Unknown
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Eq, PartialEq, Debug)]
+    struct TestHeader {
+        id: u64,
+        parent: u64,
+        number: u64,
+    }
+
+    impl From<TestHeader> for Vec<u8> {
+        fn from(_header: TestHeader) -> Vec<u8> {
+            Vec::new()
+        }
+    }
+
+    impl ByteEncodable for TestHeader {
+        type DecodeError = ();
+
+        fn decode(_bytes: &[u8]) -> Result<Self, ()> {
+            Err(())
+        }
+    }
+
+    impl Header for TestHeader {
+        type Id = u64;
+
+        fn parent(&self) -> u64 {
+            self.parent
+        }
+
+        fn number(&self) -> u64 {
+            self.number
+        }
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestBlock {
+        header: TestHeader,
+    }
+
+    fn block(id: u64, parent: u64, number: u64) -> TestBlock {
+        TestBlock { header: TestHeader { id: id, parent: parent, number: number } }
+    }
+
+    impl From<TestBlock> for Vec<u8> {
+        fn from(_block: TestBlock) -> Vec<u8> {
+            Vec::new()
+        }
+    }
+
+    impl ByteEncodable for TestBlock {
+        type DecodeError = ();
+
+        fn decode(_bytes: &[u8]) -> Result<Self, ()> {
+            Err(())
+        }
+    }
+
+    impl Block for TestBlock {
+        type Transaction = ();
+        type Header = TestHeader;
+
+        fn header(&self) -> &TestHeader {
+            &self.header
+        }
+
+        fn transactions(&self) -> &[()] {
+            &[]
+        }
+    }
+
+    struct TestProvider {
+        blocks: Vec<TestBlock>,
+    }
+
+    impl BlockProvider for TestProvider {
+        type Block = TestBlock;
+
+        fn block(&self, id: &u64) -> Option<TestBlock> {
+            self.blocks.iter().find(|b| &b.header.id == id).cloned()
+        }
+
+        fn block_id(&self, num: u64) -> Option<u64> {
+            self.blocks.iter().find(|b| b.header.number == num).map(|b| b.header.id)
+        }
+    }
+
+    // genesis(0) -> a(1) -> b(2), with a main-chain continuation b -> c(3)
+    // and a fork b -> d(13) -> e(14).
+    fn fork_provider() -> TestProvider {
+        TestProvider {
+            blocks: vec![
+                block(0, 0, 0),
+                block(1, 0, 1),
+                block(2, 1, 2),
+                block(3, 2, 3),
+                block(13, 2, 3),
+                block(14, 13, 4),
+            ],
+        }
+    }
+
+    #[test]
+    fn tree_route_no_op() {
+        let provider = fork_provider();
+        let route = provider.tree_route(&2, &2).unwrap();
+        assert_eq!(route.retracted, Vec::<u64>::new());
+        assert_eq!(route.ancestor, 2);
+        assert_eq!(route.enacted, Vec::<u64>::new());
+    }
+
+    #[test]
+    fn tree_route_pure_extension() {
+        let provider = fork_provider();
+        let route = provider.tree_route(&1, &3).unwrap();
+        assert_eq!(route.retracted, Vec::<u64>::new());
+        assert_eq!(route.ancestor, 1);
+        assert_eq!(route.enacted, vec![2, 3]);
+    }
+
+    #[test]
+    fn tree_route_pure_retraction() {
+        let provider = fork_provider();
+        let route = provider.tree_route(&3, &1).unwrap();
+        assert_eq!(route.retracted, vec![3, 2]);
+        assert_eq!(route.ancestor, 1);
+        assert_eq!(route.enacted, Vec::<u64>::new());
+    }
+
+    #[test]
+    fn tree_route_fork() {
+        let provider = fork_provider();
+        let route = provider.tree_route(&3, &14).unwrap();
+        assert_eq!(route.retracted, vec![3]);
+        assert_eq!(route.ancestor, 2);
+        assert_eq!(route.enacted, vec![13, 14]);
+    }
+
+    #[test]
+    fn tree_route_missing_ancestor_is_none() {
+        let mut provider = fork_provider();
+        provider.blocks.push(block(99, 999, 5));
+        assert!(provider.tree_route(&99, &3).is_none());
+    }
+
+    struct TestVerifier {
+        reject_unordered: Option<u64>,
+    }
+
+    impl Verifier<TestBlock> for TestVerifier {
+        type Error = ();
+
+        fn verify_basic(&self, _block: &TestBlock) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn verify_unordered(&self, block: &TestBlock) -> Result<(), ()> {
+            if Some(block.header.id) == self.reject_unordered {
+                Err(())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn verify_family(&self, _params: &FamilyParams<TestBlock>) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn verify_header(&self, _header: &TestHeader) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    struct TestChain;
+
+    impl Chain for TestChain {
+        type Block = TestBlock;
+        type Verifier = TestVerifier;
+    }
+
+    #[test]
+    fn queue_import_queues_for_phase_2() {
+        let mut queue = VerificationQueue::<TestChain>::new(TestVerifier { reject_unordered: None });
+        queue.import(block(1, 0, 1)).unwrap();
+
+        assert_eq!(queue.queue_info().unverified, 1);
+        assert_eq!(queue.status(&1), Status::Unverified);
+    }
+
+    #[test]
+    fn queue_tracks_in_flight_blocks_as_unverified() {
+        let mut queue = VerificationQueue::<TestChain>::new(TestVerifier { reject_unordered: None });
+        queue.import(block(1, 0, 1)).unwrap();
+
+        let taken = queue.next_unverified().unwrap();
+        assert_eq!(queue.queue_info().unverified, 0);
+        assert_eq!(queue.queue_info().verifying, 1);
+        assert_eq!(queue.status(&1), Status::Unverified);
+
+        let result = queue.verify_unordered(&taken);
+        queue.complete_verification(taken, result);
+
+        assert_eq!(queue.queue_info().verifying, 0);
+        assert_eq!(queue.queue_info().verified, 1);
+        assert_eq!(queue.status(&1), Status::Verified);
+    }
+
+    #[test]
+    fn queue_records_bad_blocks_from_phase_2() {
+        let mut queue = VerificationQueue::<TestChain>::new(TestVerifier { reject_unordered: Some(1) });
+        queue.import(block(1, 0, 1)).unwrap();
+
+        let taken = queue.next_unverified().unwrap();
+        let result = queue.verify_unordered(&taken);
+        queue.complete_verification(taken, result);
+
+        assert_eq!(queue.status(&1), Status::Bad);
+    }
+
+    #[test]
+    fn queue_verify_and_pop_runs_phase_3() {
+        let mut queue = VerificationQueue::<TestChain>::new(TestVerifier { reject_unordered: None });
+        queue.import(block(1, 0, 1)).unwrap();
+
+        let taken = queue.next_unverified().unwrap();
+        let result = queue.verify_unordered(&taken);
+        queue.complete_verification(taken, result);
+
+        let provider = TestProvider { blocks: Vec::new() };
+        let popped = queue.verify_and_pop(&provider, &[]).unwrap().unwrap();
+        assert_eq!(popped.header.id, 1);
+        assert_eq!(queue.queue_info().verified, 0);
+    }
+
+    #[test]
+    fn last_hashes_excludes_parent_itself() {
+        let provider = fork_provider();
+        assert_eq!(provider.last_hashes(&2, 2), vec![1, 0]);
+    }
 }
\ No newline at end of file